@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use serde::Deserialize;
 use serde_json::Value;
 
@@ -7,6 +9,24 @@ use crate::protocol::{
   RESPONSE_NODE_RAW_RESPONSE, RESPONSE_NODE_THINKING, RESPONSE_NODE_TOOL_USE,
 };
 
+mod retrieval;
+mod structured;
+mod summarizer;
+mod token_counter;
+
+pub use retrieval::RetrievalConfig;
+pub use structured::{CompactedHistoryPayload, ConversionError, HistorySummaryRenderMode};
+pub use summarizer::{
+  maybe_summarize_history, maybe_summarize_history_with_budget, maybe_summarize_history_with_mode,
+  maybe_summarize_history_with_retrieval, SummarizationBackend, SummarizationConfig, SummarizationError,
+};
+pub use token_counter::{HeuristicTokenCounter, TokenCounter};
+#[cfg(feature = "tiktoken")]
+pub use token_counter::BpeTokenCounter;
+
+use retrieval::DroppedExchangeIndex;
+use structured::build_structured_history_nodes;
+
 #[derive(Debug, Clone, Deserialize)]
 struct HistorySummaryNode {
   #[serde(default, alias = "summaryText")]
@@ -35,6 +55,16 @@ struct HistoryEndExchange {
   response_nodes: Vec<NodeIn>,
 }
 
+/// How much fidelity an individual exchange is rendered with. Exchanges
+/// start out `Full` and are progressively downgraded to `Abridged` by
+/// `render_history_summary_node_value_with_budget` when the rendered
+/// history blows past `HistoryTokenBudget::max_tokens`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExchangeRenderMode {
+  Full,
+  Abridged,
+}
+
 #[derive(Debug, Clone)]
 struct ExchangeRenderCtx {
   user_message: String,
@@ -43,6 +73,23 @@ struct ExchangeRenderCtx {
   response_text: String,
   tool_uses: Vec<ToolUseCtx>,
   has_response: bool,
+  mode: ExchangeRenderMode,
+}
+
+/// Token-budget knobs for `compact_chat_history_with_budget`. When the fully
+/// rendered `end_part_full` exceeds `max_tokens` (per `counter`), exchanges
+/// are downgraded from `render_exchange_full` to `render_exchange_abridged`,
+/// oldest first, until the render fits or everything has been abridged.
+#[derive(Clone)]
+pub struct HistoryTokenBudget {
+  pub max_tokens: usize,
+  pub counter: Arc<dyn TokenCounter>,
+}
+
+impl HistoryTokenBudget {
+  pub fn new(max_tokens: usize, counter: Arc<dyn TokenCounter>) -> Self {
+    Self { max_tokens, counter }
+  }
 }
 
 #[derive(Debug, Clone)]
@@ -160,6 +207,86 @@ fn build_exchange_render_ctx(ex: &HistoryEndExchange) -> ExchangeRenderCtx {
     response_text,
     tool_uses,
     has_response,
+    mode: ExchangeRenderMode::Full,
+  }
+}
+
+/// Number of leading/trailing characters kept on either side of the elision
+/// marker when abridging a tool result/use payload.
+const ABRIDGE_HEAD_CHARS: usize = 400;
+const ABRIDGE_TAIL_CHARS: usize = 200;
+const ABRIDGE_ELISION_MARKER: &str = "\n... [elided for history compaction] ...\n";
+
+/// Trims `s` and truncates it to a head+tail window with an elision marker
+/// in between, returning just the trimmed text if it's already short enough.
+fn abridge_text(s: &str) -> String {
+  let trimmed = s.trim();
+  let char_count = trimmed.chars().count();
+  if char_count <= ABRIDGE_HEAD_CHARS + ABRIDGE_TAIL_CHARS {
+    return trimmed.to_string();
+  }
+  let chars: Vec<char> = trimmed.chars().collect();
+  let head: String = chars[..ABRIDGE_HEAD_CHARS].iter().collect();
+  let tail: String = chars[chars.len() - ABRIDGE_TAIL_CHARS..].iter().collect();
+  format!("{head}{ABRIDGE_ELISION_MARKER}{tail}")
+}
+
+/// Abridged counterpart to `render_exchange_full`: drops `thinking`
+/// entirely and truncates tool payloads to a head+tail window, to keep an
+/// exchange's rendered size bounded regardless of how much tool output it
+/// originally carried.
+fn render_exchange_abridged(ctx: &ExchangeRenderCtx) -> String {
+  let mut out = String::new();
+  out.push_str("<exchange>\n  <user_request_or_tool_results>\n");
+  if !ctx.user_message.trim().is_empty() {
+    out.push_str(ctx.user_message.trim_end_matches('\n'));
+    out.push('\n');
+  }
+  for tr in &ctx.tool_results {
+    out.push_str(&format!(
+      "    <tool_result tool_use_id=\"{}\" is_error=\"{}\">\n",
+      tr.id.trim(),
+      if tr.is_error { "true" } else { "false" }
+    ));
+    let abridged = abridge_text(&tr.content);
+    if !abridged.is_empty() {
+      out.push_str(&abridged);
+      out.push('\n');
+    }
+    out.push_str("    </tool_result>\n");
+  }
+  out.push_str("  </user_request_or_tool_results>\n");
+
+  if !ctx.response_text.trim().is_empty() || !ctx.tool_uses.is_empty() {
+    out.push_str("  <agent_response_or_tool_uses>\n");
+    if !ctx.response_text.trim().is_empty() {
+      out.push_str(ctx.response_text.trim_end_matches('\n'));
+      out.push('\n');
+    }
+    for tu in &ctx.tool_uses {
+      out.push_str(&format!(
+        "    <tool_use name=\"{}\" tool_use_id=\"{}\">\n",
+        tu.name.trim(),
+        tu.id.trim()
+      ));
+      let abridged = abridge_text(&tu.input);
+      if !abridged.is_empty() {
+        out.push_str(&abridged);
+        out.push('\n');
+      }
+      out.push_str("    </tool_use>\n");
+    }
+    out.push_str("  </agent_response_or_tool_uses>\n");
+  }
+
+  out.push_str("</exchange>");
+  out
+}
+
+fn render_exchange(ctx: &ExchangeRenderCtx) -> String {
+  match ctx.mode {
+    ExchangeRenderMode::Full => render_exchange_full(ctx),
+    ExchangeRenderMode::Abridged => render_exchange_abridged(ctx),
   }
 }
 
@@ -225,6 +352,57 @@ fn replace_placeholders(mut template: String, repl: &[(&str, String)]) -> String
 }
 
 pub fn render_history_summary_node_value(v: &Value, extra_tool_results: &[NodeIn]) -> Option<String> {
+  render_history_summary_node_value_with_budget(v, extra_tool_results, None)
+}
+
+/// Same as `render_history_summary_node_value`, but when `budget` is set
+/// and the full-fidelity render of `history_end` exceeds it, exchanges are
+/// downgraded to `render_exchange_abridged` oldest-first until the render
+/// fits (or everything has been abridged).
+pub fn render_history_summary_node_value_with_budget(
+  v: &Value,
+  extra_tool_results: &[NodeIn],
+  budget: Option<&HistoryTokenBudget>,
+) -> Option<String> {
+  match render_history_summary_node_value_with_mode(v, extra_tool_results, budget, HistorySummaryRenderMode::XmlEmbedded, 0)? {
+    CompactedHistoryPayload::Text(text) => Some(text),
+    CompactedHistoryPayload::Nodes(_) => None,
+  }
+}
+
+/// Same as `render_history_summary_node_value_with_budget`, but `mode`
+/// selects how the tool-call portion of `history_end` is rendered.
+///
+/// `XmlEmbedded` (the default) flattens tool calls/results into the
+/// returned `CompactedHistoryPayload::Text`, same as before. `NativeToolCalls`
+/// instead returns `CompactedHistoryPayload::Nodes`: a text node carrying the
+/// rendered `message_template` (with `{end_part_full}` emptied out, since
+/// the tool-call portion moved elsewhere) followed by structured
+/// `tool_use`/`tool_result` nodes, ids starting at `starting_node_id`. If the
+/// `tool_use`/`tool_result` id-pairing invariant doesn't hold, falls back to
+/// `XmlEmbedded` rather than emitting inconsistent structured nodes.
+pub fn render_history_summary_node_value_with_mode(
+  v: &Value,
+  extra_tool_results: &[NodeIn],
+  budget: Option<&HistoryTokenBudget>,
+  mode: HistorySummaryRenderMode,
+  starting_node_id: i64,
+) -> Option<CompactedHistoryPayload> {
+  render_history_summary_node_value_with_retrieval(v, extra_tool_results, budget, mode, starting_node_id, &[])
+}
+
+/// Same as `render_history_summary_node_value_with_mode`, but `retrieved_blocks`
+/// — rendered abridged exchanges a `DroppedExchangeIndex` retrieved as
+/// relevant to the current turn — are appended after `{middle_part_abridged}`.
+/// Pass an empty slice to behave exactly like `render_history_summary_node_value_with_mode`.
+pub fn render_history_summary_node_value_with_retrieval(
+  v: &Value,
+  extra_tool_results: &[NodeIn],
+  budget: Option<&HistoryTokenBudget>,
+  mode: HistorySummaryRenderMode,
+  starting_node_id: i64,
+  retrieved_blocks: &[String],
+) -> Option<CompactedHistoryPayload> {
   let mut node: HistorySummaryNode = serde_json::from_value(v.clone()).ok()?;
   if node.message_template.trim().is_empty() {
     return None;
@@ -239,15 +417,82 @@ pub fn render_history_summary_node_value(v: &Value, extra_tool_results: &[NodeIn
     });
   }
 
-  let end_part_full = node
-    .history_end
-    .iter()
-    .map(build_exchange_render_ctx)
-    .map(|ctx| render_exchange_full(&ctx))
-    .collect::<Vec<_>>()
-    .join("\n");
+  let mut ctxs: Vec<ExchangeRenderCtx> = node.history_end.iter().map(build_exchange_render_ctx).collect();
+  let mut rendered_parts: Vec<String> = ctxs.iter().map(render_exchange).collect();
+
+  if let Some(budget) = budget {
+    // `rendered_parts` is eventually joined with "\n" separators (see
+    // `end_part_full` below), so fold in their token cost too — otherwise
+    // this check under-counts relative to what's actually emitted.
+    let separator_tokens = budget.counter.count("\n") * rendered_parts.len().saturating_sub(1);
+    let mut total_tokens: usize = rendered_parts.iter().map(|p| budget.counter.count(p)).sum::<usize>() + separator_tokens;
+    if total_tokens > budget.max_tokens {
+      for (ctx, part) in ctxs.iter_mut().zip(rendered_parts.iter_mut()) {
+        let old_tokens = budget.counter.count(part);
+        ctx.mode = ExchangeRenderMode::Abridged;
+        *part = render_exchange(ctx);
+        let new_tokens = budget.counter.count(part);
+        total_tokens = total_tokens - old_tokens + new_tokens;
+        if total_tokens <= budget.max_tokens {
+          break;
+        }
+      }
+    }
+  }
+
+  let end_part_full = rendered_parts.join("\n");
+
+  let mut middle_abridged = node.history_middle_abridged_text.clone();
+  if !retrieved_blocks.is_empty() {
+    if !middle_abridged.trim().is_empty() {
+      middle_abridged.push('\n');
+    }
+    middle_abridged.push_str(&retrieved_blocks.join("\n"));
+  }
+
+  if mode == HistorySummaryRenderMode::NativeToolCalls {
+    if let Ok(structured_nodes) = build_structured_history_nodes(&ctxs, starting_node_id + 1) {
+      let template_text = replace_placeholders(
+        node.message_template.clone(),
+        &[
+          ("{summary}", node.summary_text.clone()),
+          ("{summarization_request_id}", node.summarization_request_id.clone()),
+          (
+            "{beginning_part_dropped_num_exchanges}",
+            node.history_beginning_dropped_num_exchanges.to_string(),
+          ),
+          ("{middle_part_abridged}", middle_abridged.clone()),
+          ("{end_part_full}", String::new()),
+          ("{abridged_history}", middle_abridged),
+        ],
+      );
+      let template_node = NodeIn {
+        id: starting_node_id,
+        node_type: REQUEST_NODE_TEXT,
+        content: String::new(),
+        text_node: Some(TextNode { content: template_text }),
+        tool_result_node: None,
+        image_node: None,
+        image_id_node: None,
+        ide_state_node: None,
+        edit_events_node: None,
+        checkpoint_ref_node: None,
+        change_personality_node: None,
+        file_node: None,
+        file_id_node: None,
+        history_summary_node: None,
+        tool_use: None,
+        thinking: None,
+      };
+
+      let mut nodes = Vec::with_capacity(1 + structured_nodes.len());
+      nodes.push(template_node);
+      nodes.extend(structured_nodes);
+      return Some(CompactedHistoryPayload::Nodes(nodes));
+    }
+    // id-pairing invariant violated: fall through to the XML-embedded render.
+  }
 
-  let abridged = node.history_middle_abridged_text.clone();
   let rendered = replace_placeholders(
     node.message_template.clone(),
     &[
@@ -257,14 +502,14 @@ pub fn render_history_summary_node_value(v: &Value, extra_tool_results: &[NodeIn
         "{beginning_part_dropped_num_exchanges}",
         node.history_beginning_dropped_num_exchanges.to_string(),
       ),
-      ("{middle_part_abridged}", abridged.clone()),
+      ("{middle_part_abridged}", middle_abridged.clone()),
       ("{end_part_full}", end_part_full),
       // 兼容旧模板字段名
-      ("{abridged_history}", abridged),
+      ("{abridged_history}", middle_abridged),
     ],
   );
 
-  Some(rendered)
+  Some(CompactedHistoryPayload::Text(rendered))
 }
 
 fn chat_history_item_has_summary(item: &AugmentChatHistory) -> bool {
@@ -273,11 +518,78 @@ fn chat_history_item_has_summary(item: &AugmentChatHistory) -> bool {
     || has_history_summary_node(&item.nodes)
 }
 
+/// Builds an `ExchangeRenderCtx` straight from a raw `AugmentChatHistory`
+/// item, reusing the same request/response-node extraction logic that
+/// `build_exchange_render_ctx` applies to `HistoryEndExchange`. Used by the
+/// fallback summarizer to render the oldest exchanges into a prompt without
+/// duplicating the extraction rules.
+fn exchange_render_ctx_from_chat_item(item: &AugmentChatHistory) -> ExchangeRenderCtx {
+  let request_nodes = if !item.request_nodes.is_empty() {
+    item.request_nodes.clone()
+  } else if !item.structured_request_nodes.is_empty() {
+    item.structured_request_nodes.clone()
+  } else {
+    item.nodes.clone()
+  };
+
+  build_exchange_render_ctx(&HistoryEndExchange {
+    request_message: item.request_message.clone(),
+    response_text: item.response_text.clone(),
+    request_nodes,
+    response_nodes: item.response_nodes.clone(),
+  })
+}
+
 pub fn compact_chat_history(chat_history: &mut Vec<AugmentChatHistory>) {
+  compact_chat_history_with_budget(chat_history, None)
+}
+
+/// Same as `compact_chat_history`, but threads a `HistoryTokenBudget`
+/// through to `render_history_summary_node_value_with_budget` so the
+/// rendered history is progressively abridged instead of always rendering
+/// `history_end` at full fidelity.
+pub fn compact_chat_history_with_budget(
+  chat_history: &mut Vec<AugmentChatHistory>,
+  budget: Option<&HistoryTokenBudget>,
+) {
+  compact_chat_history_with_mode(chat_history, budget, HistorySummaryRenderMode::XmlEmbedded)
+}
+
+/// Same as `compact_chat_history_with_budget`, but `mode` selects between
+/// the XML-flattened render (default, needed for backends without native
+/// function-calling support) and the structured `NativeToolCalls` render —
+/// see `render_history_summary_node_value_with_mode`.
+pub fn compact_chat_history_with_mode(
+  chat_history: &mut Vec<AugmentChatHistory>,
+  budget: Option<&HistoryTokenBudget>,
+  mode: HistorySummaryRenderMode,
+) {
+  compact_chat_history_with_retrieval(chat_history, budget, mode, &RetrievalConfig::default())
+}
+
+/// Same as `compact_chat_history_with_mode`, but before `chat_history[0..start]`
+/// is drained, those dropped exchanges are indexed with `DroppedExchangeIndex`
+/// and the most relevant ones to the latest user message are re-injected as
+/// abridged blocks appended to `{middle_part_abridged}`, per `retrieval`.
+/// When there's nothing to drop (or nothing in the index scores above
+/// `retrieval.min_score`), nothing is appended to `{middle_part_abridged}`.
+pub fn compact_chat_history_with_retrieval(
+  chat_history: &mut Vec<AugmentChatHistory>,
+  budget: Option<&HistoryTokenBudget>,
+  mode: HistorySummaryRenderMode,
+  retrieval: &RetrievalConfig,
+) {
   let Some(start) = chat_history.iter().rposition(chat_history_item_has_summary) else {
     return;
   };
 
+  let query = chat_history.last().map(|h| h.request_message.clone()).unwrap_or_default();
+  let dropped_ctxs: Vec<ExchangeRenderCtx> = chat_history[0..start]
+    .iter()
+    .map(exchange_render_ctx_from_chat_item)
+    .collect();
+  let dropped_index = DroppedExchangeIndex::build(&dropped_ctxs);
+
   if start > 0 {
     chat_history.drain(0..start);
   }
@@ -301,6 +613,7 @@ pub fn compact_chat_history(chat_history: &mut Vec<AugmentChatHistory>) {
     .history_summary_node
     .clone()
     .unwrap_or(Value::Null);
+  let max_id = req_nodes.iter().map(|n| n.id).max().unwrap_or(summary_id);
 
   let tool_results: Vec<NodeIn> = req_nodes
     .iter()
@@ -308,39 +621,55 @@ pub fn compact_chat_history(chat_history: &mut Vec<AugmentChatHistory>) {
     .cloned()
     .collect();
 
-  let Some(text) = render_history_summary_node_value(&summary_value, &tool_results) else {
+  let retrieved_blocks = if dropped_index.is_empty() {
+    Vec::new()
+  } else {
+    dropped_index.top_k(&query, retrieval)
+  };
+
+  let Some(payload) = render_history_summary_node_value_with_retrieval(
+    &summary_value,
+    &tool_results,
+    budget,
+    mode,
+    max_id + 1,
+    &retrieved_blocks,
+  ) else {
     // 无法渲染时，不做破坏性改写；仅保留裁剪 chat_history 的行为。
     first.request_nodes = req_nodes;
     return;
   };
 
-  let mut other_nodes: Vec<NodeIn> = req_nodes
+  let other_nodes: Vec<NodeIn> = req_nodes
     .into_iter()
     .filter(|n| n.node_type != REQUEST_NODE_HISTORY_SUMMARY && n.node_type != REQUEST_NODE_TOOL_RESULT)
     .collect();
 
-  let summary_text_node = NodeIn {
-    id: summary_id,
-    node_type: REQUEST_NODE_TEXT,
-    content: String::new(),
-    text_node: Some(TextNode { content: text }),
-    tool_result_node: None,
-    image_node: None,
-    image_id_node: None,
-    ide_state_node: None,
-    edit_events_node: None,
-    checkpoint_ref_node: None,
-    change_personality_node: None,
-    file_node: None,
-    file_id_node: None,
-    history_summary_node: None,
-    tool_use: None,
-    thinking: None,
+  let mut new_nodes = match payload {
+    CompactedHistoryPayload::Text(text) => {
+      vec![NodeIn {
+        id: summary_id,
+        node_type: REQUEST_NODE_TEXT,
+        content: String::new(),
+        text_node: Some(TextNode { content: text }),
+        tool_result_node: None,
+        image_node: None,
+        image_id_node: None,
+        ide_state_node: None,
+        edit_events_node: None,
+        checkpoint_ref_node: None,
+        change_personality_node: None,
+        file_node: None,
+        file_id_node: None,
+        history_summary_node: None,
+        tool_use: None,
+        thinking: None,
+      }]
+    }
+    CompactedHistoryPayload::Nodes(nodes) => nodes,
   };
 
-  let mut new_nodes = Vec::with_capacity(1 + other_nodes.len());
-  new_nodes.push(summary_text_node);
-  new_nodes.append(&mut other_nodes);
+  new_nodes.extend(other_nodes);
   first.request_nodes = new_nodes;
 }
 
@@ -603,4 +932,321 @@ Beginning part has {beginning_part_dropped_num_exchanges} exchanges.
       "render failed -> should keep tool_result nodes"
     );
   }
+
+  struct FixedTokenCounter(usize);
+
+  impl TokenCounter for FixedTokenCounter {
+    fn count(&self, s: &str) -> usize {
+      s.len() / self.0.max(1)
+    }
+  }
+
+  #[test]
+  fn renders_full_fidelity_when_under_budget() {
+    let v = serde_json::json!({
+      "summary_text": "SUM",
+      "history_end": [
+        {
+          "request_message": "hello",
+          "response_text": "",
+          "request_nodes": [],
+          "response_nodes": [
+            { "id": 1, "type": 0, "content": "a very long thinking-free response" }
+          ]
+        }
+      ],
+      "message_template": "<end_part_full>{end_part_full}</end_part_full>"
+    });
+
+    let budget = HistoryTokenBudget::new(10_000, Arc::new(HeuristicTokenCounter));
+    let rendered = render_history_summary_node_value_with_budget(&v, &[], Some(&budget)).expect("should render");
+    assert!(rendered.contains("<user_request_or_tool_results>"));
+    assert!(!rendered.contains("[elided for history compaction]"));
+  }
+
+  #[test]
+  fn abridges_oldest_exchange_first_when_over_budget() {
+    let long_tool_result = NodeIn {
+      id: 1,
+      node_type: REQUEST_NODE_TOOL_RESULT,
+      content: String::new(),
+      text_node: None,
+      tool_result_node: Some(ToolResultNode {
+        tool_use_id: "t1".to_string(),
+        content: "x".repeat(2000),
+        content_nodes: Vec::new(),
+        is_error: false,
+      }),
+      image_node: None,
+      image_id_node: None,
+      ide_state_node: None,
+      edit_events_node: None,
+      checkpoint_ref_node: None,
+      change_personality_node: None,
+      file_node: None,
+      file_id_node: None,
+      history_summary_node: None,
+      tool_use: None,
+      thinking: None,
+    };
+
+    let v = serde_json::json!({
+      "summary_text": "SUM",
+      "history_end": [
+        {
+          "request_message": "oldest",
+          "response_text": "",
+          "request_nodes": [serde_json::to_value(&long_tool_result).unwrap()],
+          "response_nodes": []
+        },
+        {
+          "request_message": "newest",
+          "response_text": "short reply",
+          "request_nodes": [],
+          "response_nodes": []
+        }
+      ],
+      "message_template": "<end_part_full>{end_part_full}</end_part_full>"
+    });
+
+    // Every char counts as one token, so anything past a tiny budget forces abridging.
+    let budget = HistoryTokenBudget::new(100, Arc::new(FixedTokenCounter(1)));
+    let rendered = render_history_summary_node_value_with_budget(&v, &[], Some(&budget)).expect("should render");
+    assert!(rendered.contains("[elided for history compaction]"));
+    assert!(rendered.contains("newest"));
+    assert!(rendered.contains("short reply"));
+  }
+
+  fn make_chat_item(request_message: &str, response_text: &str, request_nodes: Vec<NodeIn>) -> AugmentChatHistory {
+    AugmentChatHistory {
+      response_text: response_text.to_string(),
+      request_message: request_message.to_string(),
+      request_id: "r".to_string(),
+      request_nodes,
+      structured_request_nodes: Vec::new(),
+      nodes: Vec::new(),
+      response_nodes: Vec::new(),
+      structured_output_nodes: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn re_injects_relevant_dropped_exchange_into_middle_abridged() {
+    let summary = serde_json::json!({
+      "summary_text": "S",
+      "history_end": [],
+      "message_template": "<middle_part_abridged>{middle_part_abridged}</middle_part_abridged>"
+    });
+
+    let summary_node = NodeIn {
+      id: 1,
+      node_type: REQUEST_NODE_HISTORY_SUMMARY,
+      content: String::new(),
+      text_node: None,
+      tool_result_node: None,
+      image_node: None,
+      image_id_node: None,
+      ide_state_node: None,
+      edit_events_node: None,
+      checkpoint_ref_node: None,
+      change_personality_node: None,
+      file_node: None,
+      file_id_node: None,
+      history_summary_node: Some(summary),
+      tool_use: None,
+      thinking: None,
+    };
+
+    let mut chat_history = vec![
+      make_chat_item(
+        "how do I configure the database connection pool",
+        "set pool_size in config.toml",
+        Vec::new(),
+      ),
+      make_chat_item("what's a good recipe for soup", "simmer vegetables for twenty minutes", Vec::new()),
+      make_chat_item("database connection pool settings", "", vec![summary_node]),
+    ];
+
+    compact_chat_history(&mut chat_history);
+
+    assert_eq!(chat_history.len(), 1);
+    let txt = chat_history[0]
+      .request_nodes
+      .iter()
+      .find(|n| n.node_type == REQUEST_NODE_TEXT)
+      .and_then(|n| n.text_node.as_ref())
+      .map(|t| t.content.clone())
+      .unwrap_or_default();
+    assert!(txt.contains("pool_size"), "relevant dropped exchange should be re-injected: {txt}");
+    assert!(!txt.contains("simmer"), "irrelevant dropped exchange should not be re-injected: {txt}");
+  }
+
+  #[test]
+  fn skips_reinjection_when_nothing_is_dropped() {
+    let summary = serde_json::json!({
+      "summary_text": "S",
+      "history_end": [],
+      "message_template": "<middle_part_abridged>{middle_part_abridged}</middle_part_abridged>"
+    });
+
+    let summary_node = NodeIn {
+      id: 1,
+      node_type: REQUEST_NODE_HISTORY_SUMMARY,
+      content: String::new(),
+      text_node: None,
+      tool_result_node: None,
+      image_node: None,
+      image_id_node: None,
+      ide_state_node: None,
+      edit_events_node: None,
+      checkpoint_ref_node: None,
+      change_personality_node: None,
+      file_node: None,
+      file_id_node: None,
+      history_summary_node: Some(summary),
+      tool_use: None,
+      thinking: None,
+    };
+
+    let mut chat_history = vec![make_chat_item("database pool settings", "", vec![summary_node])];
+
+    compact_chat_history(&mut chat_history);
+
+    assert_eq!(chat_history.len(), 1);
+    let txt = chat_history[0]
+      .request_nodes
+      .iter()
+      .find(|n| n.node_type == REQUEST_NODE_TEXT)
+      .and_then(|n| n.text_node.as_ref())
+      .map(|t| t.content.clone())
+      .unwrap_or_default();
+    assert_eq!(txt, "<middle_part_abridged></middle_part_abridged>");
+  }
+
+  #[test]
+  fn compact_chat_history_with_mode_native_tool_calls_emits_structured_nodes() {
+    use crate::protocol::ToolUseNode;
+
+    let tool_use_node = NodeIn {
+      id: 1,
+      node_type: RESPONSE_NODE_TOOL_USE,
+      content: String::new(),
+      text_node: None,
+      tool_result_node: None,
+      image_node: None,
+      image_id_node: None,
+      ide_state_node: None,
+      edit_events_node: None,
+      checkpoint_ref_node: None,
+      change_personality_node: None,
+      file_node: None,
+      file_id_node: None,
+      history_summary_node: None,
+      tool_use: Some(ToolUseNode {
+        tool_name: "read_file".to_string(),
+        tool_use_id: "t1".to_string(),
+        input_json: r#"{"path":"a.rs"}"#.to_string(),
+      }),
+      thinking: None,
+    };
+
+    let tool_result_node = NodeIn {
+      id: 2,
+      node_type: REQUEST_NODE_TOOL_RESULT,
+      content: String::new(),
+      text_node: None,
+      tool_result_node: Some(ToolResultNode {
+        tool_use_id: "t1".to_string(),
+        content: "CONTENTS".to_string(),
+        content_nodes: vec![crate::protocol::ToolResultContentNode {
+          node_type: TOOL_RESULT_CONTENT_NODE_TEXT,
+          text_content: "CONTENTS".to_string(),
+          image_content: None,
+        }],
+        is_error: false,
+      }),
+      image_node: None,
+      image_id_node: None,
+      ide_state_node: None,
+      edit_events_node: None,
+      checkpoint_ref_node: None,
+      change_personality_node: None,
+      file_node: None,
+      file_id_node: None,
+      history_summary_node: None,
+      tool_use: None,
+      thinking: None,
+    };
+
+    let summary = serde_json::json!({
+      "summary_text": "S",
+      "history_end": [
+        {
+          "request_message": "read file a.rs",
+          "response_text": "",
+          "request_nodes": [],
+          "response_nodes": [serde_json::to_value(&tool_use_node).unwrap()]
+        },
+        {
+          "request_message": "",
+          "response_text": "",
+          "request_nodes": [serde_json::to_value(&tool_result_node).unwrap()],
+          "response_nodes": []
+        }
+      ],
+      "message_template": "<supervisor>{middle_part_abridged}<end_part_full>{end_part_full}</end_part_full></supervisor>"
+    });
+
+    let summary_node = NodeIn {
+      id: 1,
+      node_type: REQUEST_NODE_HISTORY_SUMMARY,
+      content: String::new(),
+      text_node: None,
+      tool_result_node: None,
+      image_node: None,
+      image_id_node: None,
+      ide_state_node: None,
+      edit_events_node: None,
+      checkpoint_ref_node: None,
+      change_personality_node: None,
+      file_node: None,
+      file_id_node: None,
+      history_summary_node: Some(summary),
+      tool_use: None,
+      thinking: None,
+    };
+
+    let mut chat_history = vec![make_chat_item("anything about a.rs?", "", vec![summary_node])];
+
+    compact_chat_history_with_mode(&mut chat_history, None, HistorySummaryRenderMode::NativeToolCalls);
+
+    assert_eq!(chat_history.len(), 1);
+    let nodes = &chat_history[0].request_nodes;
+
+    // The {end_part_full} placeholder is emptied out in native mode — the
+    // tool call/result live as their own structured nodes instead.
+    let template_text = nodes
+      .iter()
+      .find(|n| n.node_type == REQUEST_NODE_TEXT)
+      .and_then(|n| n.text_node.as_ref())
+      .map(|t| t.content.clone())
+      .unwrap_or_default();
+    assert!(!template_text.contains("tool_use"), "tool call should not be XML-flattened into the template text");
+
+    let structured_tool_use = nodes
+      .iter()
+      .find(|n| n.node_type == RESPONSE_NODE_TOOL_USE)
+      .and_then(|n| n.tool_use.as_ref())
+      .expect("should carry a structured tool_use node");
+    assert_eq!(structured_tool_use.tool_use_id, "t1");
+    assert_eq!(structured_tool_use.input_json, r#"{"path":"a.rs"}"#);
+
+    let structured_tool_result = nodes
+      .iter()
+      .find(|n| n.node_type == REQUEST_NODE_TOOL_RESULT)
+      .and_then(|n| n.tool_result_node.as_ref())
+      .expect("should carry a structured tool_result node");
+    assert_eq!(structured_tool_result.tool_use_id, "t1");
+    assert_eq!(structured_tool_result.content, "CONTENTS");
+  }
 }
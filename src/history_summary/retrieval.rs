@@ -0,0 +1,202 @@
+//! BM25 retrieval over the exchanges `compact_chat_history` drops when it
+//! drains `chat_history[0..start]`. Those exchanges are gone from the
+//! rendered history entirely, even when the current user turn is actually
+//! about something discussed in one of them. This indexes the dropped
+//! exchanges as documents and, given the latest user message as a query,
+//! surfaces the most relevant ones so they can be re-injected as abridged
+//! blocks rather than lost outright.
+
+use std::collections::HashMap;
+
+use super::{render_exchange_abridged, ExchangeRenderCtx};
+
+/// BM25 term-frequency saturation knob. Standard default.
+const K1: f64 = 1.2;
+/// BM25 document-length normalization knob. Standard default.
+const B: f64 = 0.75;
+
+/// Tuning for `DroppedExchangeIndex::top_k`.
+#[derive(Debug, Clone)]
+pub struct RetrievalConfig {
+  /// Maximum number of dropped exchanges to re-inject.
+  pub top_k: usize,
+  /// Matches scoring at or below this are dropped even if they'd fit in
+  /// `top_k`, so an irrelevant index doesn't pad the prompt with noise.
+  pub min_score: f64,
+}
+
+impl Default for RetrievalConfig {
+  fn default() -> Self {
+    Self { top_k: 3, min_score: 0.0 }
+  }
+}
+
+/// Lowercases and splits on unicode word boundaries (anything that isn't
+/// alphanumeric), which is all BM25 needs out of a tokenizer here.
+fn tokenize(s: &str) -> Vec<String> {
+  s.split(|c: char| !c.is_alphanumeric())
+    .filter(|t| !t.is_empty())
+    .map(|t| t.to_lowercase())
+    .collect()
+}
+
+struct IndexedDoc {
+  term_freq: HashMap<String, usize>,
+  len: usize,
+  /// The abridged block to re-inject if this document is retrieved.
+  rendered: String,
+}
+
+/// A BM25 index over the exchanges `compact_chat_history` is about to drop.
+/// Built once per compaction pass from the dropped `ExchangeRenderCtx`s.
+pub(crate) struct DroppedExchangeIndex {
+  docs: Vec<IndexedDoc>,
+  doc_freq: HashMap<String, usize>,
+  avgdl: f64,
+}
+
+impl DroppedExchangeIndex {
+  /// Indexes `exchanges`, concatenating each one's `user_message` and
+  /// `response_text` as its document text. `exchanges` being empty produces
+  /// an empty index, so `top_k` always returns nothing for it.
+  pub(crate) fn build(exchanges: &[ExchangeRenderCtx]) -> Self {
+    let mut docs = Vec::with_capacity(exchanges.len());
+    let mut doc_freq: HashMap<String, usize> = HashMap::new();
+    let mut total_len = 0usize;
+
+    for ctx in exchanges {
+      let text = format!("{} {}", ctx.user_message, ctx.response_text);
+      let tokens = tokenize(&text);
+      let len = tokens.len();
+      total_len += len;
+
+      let mut term_freq: HashMap<String, usize> = HashMap::new();
+      for token in tokens {
+        *term_freq.entry(token).or_insert(0) += 1;
+      }
+      for term in term_freq.keys() {
+        *doc_freq.entry(term.clone()).or_insert(0) += 1;
+      }
+
+      docs.push(IndexedDoc {
+        term_freq,
+        len,
+        rendered: render_exchange_abridged(ctx),
+      });
+    }
+
+    let avgdl = if docs.is_empty() {
+      0.0
+    } else {
+      total_len as f64 / docs.len() as f64
+    };
+
+    Self { docs, doc_freq, avgdl }
+  }
+
+  pub(crate) fn is_empty(&self) -> bool {
+    self.docs.is_empty()
+  }
+
+  fn idf(&self, term: &str) -> f64 {
+    let n = self.docs.len() as f64;
+    let n_t = self.doc_freq.get(term).copied().unwrap_or(0) as f64;
+    ((n - n_t + 0.5) / (n_t + 0.5) + 1.0).ln()
+  }
+
+  fn score(&self, query_terms: &[String], doc: &IndexedDoc) -> f64 {
+    query_terms
+      .iter()
+      .map(|term| {
+        let f = doc.term_freq.get(term).copied().unwrap_or(0) as f64;
+        if f == 0.0 {
+          return 0.0;
+        }
+        let numerator = f * (K1 + 1.0);
+        let denominator = f + K1 * (1.0 - B + B * doc.len as f64 / self.avgdl.max(1.0));
+        self.idf(term) * numerator / denominator
+      })
+      .sum()
+  }
+
+  /// Ranks every indexed document against `query` and returns the rendered
+  /// abridged blocks for the top `config.top_k` whose score exceeds
+  /// `config.min_score`, highest-scoring first.
+  pub(crate) fn top_k(&self, query: &str, config: &RetrievalConfig) -> Vec<String> {
+    if self.is_empty() || config.top_k == 0 {
+      return Vec::new();
+    }
+
+    let query_terms = tokenize(query);
+    if query_terms.is_empty() {
+      return Vec::new();
+    }
+
+    let mut scored: Vec<(f64, &str)> = self
+      .docs
+      .iter()
+      .map(|doc| (self.score(&query_terms, doc), doc.rendered.as_str()))
+      .filter(|(score, _)| *score > config.min_score)
+      .collect();
+
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored
+      .into_iter()
+      .take(config.top_k)
+      .map(|(_, rendered)| rendered.to_string())
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ctx(user_message: &str, response_text: &str) -> ExchangeRenderCtx {
+    ExchangeRenderCtx {
+      user_message: user_message.to_string(),
+      tool_results: Vec::new(),
+      thinking: String::new(),
+      response_text: response_text.to_string(),
+      tool_uses: Vec::new(),
+      has_response: !response_text.is_empty(),
+      mode: super::super::ExchangeRenderMode::Full,
+    }
+  }
+
+  #[test]
+  fn empty_index_retrieves_nothing() {
+    let index = DroppedExchangeIndex::build(&[]);
+    assert!(index.is_empty());
+    assert!(index.top_k("anything", &RetrievalConfig::default()).is_empty());
+  }
+
+  #[test]
+  fn ranks_the_more_relevant_document_first() {
+    let exchanges = vec![
+      ctx("how do I configure the database connection pool", "set pool_size in config.toml"),
+      ctx("what's the weather like today", "sunny with a chance of rain"),
+    ];
+    let index = DroppedExchangeIndex::build(&exchanges);
+
+    let results = index.top_k("database connection pool settings", &RetrievalConfig::default());
+    assert_eq!(results.len(), 1);
+    assert!(results[0].contains("pool_size"));
+  }
+
+  #[test]
+  fn respects_top_k_and_min_score() {
+    let exchanges = vec![
+      ctx("rust error handling patterns", "use Result and the ? operator"),
+      ctx("rust async runtime choice", "tokio is the common default"),
+      ctx("unrelated cooking question", "simmer for twenty minutes"),
+    ];
+    let index = DroppedExchangeIndex::build(&exchanges);
+
+    let limited = index.top_k("rust", &RetrievalConfig { top_k: 1, min_score: 0.0 });
+    assert_eq!(limited.len(), 1);
+
+    let strict = index.top_k("rust", &RetrievalConfig { top_k: 10, min_score: 1000.0 });
+    assert!(strict.is_empty());
+  }
+}
@@ -0,0 +1,331 @@
+//! Native structured tool-call rendering: instead of flattening
+//! `tool_uses`/`tool_results` into XML-ish tags inside one text blob, emit
+//! them as their own `NodeIn` entries so backends with real function-calling
+//! support (tool_calls/tool_use content blocks) see structured data instead
+//! of embedded markup.
+
+use std::collections::HashSet;
+
+use crate::protocol::{
+  NodeIn, TextNode, ToolResultContentNode, ToolResultNode, ToolUseNode, REQUEST_NODE_TEXT, REQUEST_NODE_TOOL_RESULT,
+  RESPONSE_NODE_TOOL_USE, TOOL_RESULT_CONTENT_NODE_TEXT,
+};
+
+use super::{abridge_text, ExchangeRenderCtx, ExchangeRenderMode, ToolResultCtx, ToolUseCtx};
+
+/// How `compact_chat_history` renders the tool-call portion of an exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistorySummaryRenderMode {
+  /// Flatten everything (including tool calls/results) into one
+  /// `REQUEST_NODE_TEXT` node with XML-ish tags. The default — needed for
+  /// backends without native function-calling support.
+  XmlEmbedded,
+  /// Emit the narrative portion as text, but tool calls/results as their
+  /// own structured `NodeIn` entries keyed by `tool_use_id`.
+  NativeToolCalls,
+}
+
+impl Default for HistorySummaryRenderMode {
+  fn default() -> Self {
+    HistorySummaryRenderMode::XmlEmbedded
+  }
+}
+
+/// What a compacted history render produced, for either render mode.
+#[derive(Debug, Clone)]
+pub enum CompactedHistoryPayload {
+  Text(String),
+  Nodes(Vec<NodeIn>),
+}
+
+/// An exchange's tool_use was never answered by a later tool_result while
+/// converting to structured nodes. Exchanges from the most recent turn are
+/// exempt, since their tool calls may still be awaiting a result outside
+/// the rendered window.
+#[derive(Debug, Clone)]
+pub struct ConversionError {
+  pub unmatched_tool_use_ids: Vec<String>,
+}
+
+impl std::fmt::Display for ConversionError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "tool_use id(s) without a matching tool_result: {:?}",
+      self.unmatched_tool_use_ids
+    )
+  }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// Every `tool_use` id must be matched by a later `tool_result` id, except
+/// ones introduced by the last exchange that actually has `tool_uses` (those
+/// may still be pending). Note this isn't necessarily `ctxs.last()` — a
+/// caller (e.g. `render_history_summary_node_value_with_retrieval`) may
+/// append a synthetic trailing exchange carrying only `tool_results` for the
+/// current turn, which shouldn't shift the exemption onto an exchange with
+/// no `tool_uses` of its own.
+fn validate_tool_id_pairing(ctxs: &[ExchangeRenderCtx]) -> Result<(), ConversionError> {
+  let mut outstanding: HashSet<String> = HashSet::new();
+  for ctx in ctxs {
+    for tr in &ctx.tool_results {
+      outstanding.remove(&tr.id);
+    }
+    for tu in &ctx.tool_uses {
+      outstanding.insert(tu.id.clone());
+    }
+  }
+
+  let final_tool_use_ids: HashSet<&String> = ctxs
+    .iter()
+    .rev()
+    .find(|c| !c.tool_uses.is_empty())
+    .map(|c| c.tool_uses.iter().map(|t| &t.id).collect())
+    .unwrap_or_default();
+
+  let mut unmatched: Vec<String> = outstanding
+    .into_iter()
+    .filter(|id| !final_tool_use_ids.contains(id))
+    .collect();
+  if unmatched.is_empty() {
+    Ok(())
+  } else {
+    unmatched.sort();
+    Err(ConversionError { unmatched_tool_use_ids: unmatched })
+  }
+}
+
+/// Renders the narrative (user message / thinking / response text) portion
+/// of an exchange, leaving tool calls and results out entirely — those
+/// become their own structured nodes. Mirrors `render_exchange_abridged`'s
+/// choice to drop `thinking` once an exchange has been downgraded.
+fn render_exchange_narrative(ctx: &ExchangeRenderCtx) -> Option<String> {
+  let include_thinking = ctx.mode == ExchangeRenderMode::Full && !ctx.thinking.trim().is_empty();
+  if ctx.user_message.trim().is_empty() && !include_thinking && ctx.response_text.trim().is_empty() {
+    return None;
+  }
+
+  let mut out = String::new();
+  out.push_str("<exchange>\n");
+  if !ctx.user_message.trim().is_empty() {
+    out.push_str("  <user_request>\n");
+    out.push_str(ctx.user_message.trim());
+    out.push_str("\n  </user_request>\n");
+  }
+  if include_thinking || !ctx.response_text.trim().is_empty() {
+    out.push_str("  <agent_response>\n");
+    if include_thinking {
+      out.push_str("    <thinking>\n");
+      out.push_str(ctx.thinking.trim());
+      out.push_str("\n    </thinking>\n");
+    }
+    if !ctx.response_text.trim().is_empty() {
+      out.push_str(ctx.response_text.trim());
+      out.push('\n');
+    }
+    out.push_str("  </agent_response>\n");
+  }
+  out.push_str("</exchange>");
+  Some(out)
+}
+
+fn make_text_node(id: i64, content: String) -> NodeIn {
+  NodeIn {
+    id,
+    node_type: REQUEST_NODE_TEXT,
+    content: String::new(),
+    text_node: Some(TextNode { content }),
+    tool_result_node: None,
+    image_node: None,
+    image_id_node: None,
+    ide_state_node: None,
+    edit_events_node: None,
+    checkpoint_ref_node: None,
+    change_personality_node: None,
+    file_node: None,
+    file_id_node: None,
+    history_summary_node: None,
+    tool_use: None,
+    thinking: None,
+  }
+}
+
+fn make_tool_result_node(id: i64, tr: &ToolResultCtx, mode: ExchangeRenderMode) -> NodeIn {
+  let content = match mode {
+    ExchangeRenderMode::Full => tr.content.clone(),
+    ExchangeRenderMode::Abridged => abridge_text(&tr.content),
+  };
+  NodeIn {
+    id,
+    node_type: REQUEST_NODE_TOOL_RESULT,
+    content: String::new(),
+    text_node: None,
+    tool_result_node: Some(ToolResultNode {
+      tool_use_id: tr.id.clone(),
+      content: content.clone(),
+      content_nodes: vec![ToolResultContentNode {
+        node_type: TOOL_RESULT_CONTENT_NODE_TEXT,
+        text_content: content,
+        image_content: None,
+      }],
+      is_error: tr.is_error,
+    }),
+    image_node: None,
+    image_id_node: None,
+    ide_state_node: None,
+    edit_events_node: None,
+    checkpoint_ref_node: None,
+    change_personality_node: None,
+    file_node: None,
+    file_id_node: None,
+    history_summary_node: None,
+    tool_use: None,
+    thinking: None,
+  }
+}
+
+fn make_tool_use_node(id: i64, tu: &ToolUseCtx, mode: ExchangeRenderMode) -> NodeIn {
+  let input = match mode {
+    ExchangeRenderMode::Full => tu.input.clone(),
+    ExchangeRenderMode::Abridged => abridge_text(&tu.input),
+  };
+  NodeIn {
+    id,
+    node_type: RESPONSE_NODE_TOOL_USE,
+    content: String::new(),
+    text_node: None,
+    tool_result_node: None,
+    image_node: None,
+    image_id_node: None,
+    ide_state_node: None,
+    edit_events_node: None,
+    checkpoint_ref_node: None,
+    change_personality_node: None,
+    file_node: None,
+    file_id_node: None,
+    history_summary_node: None,
+    tool_use: Some(ToolUseNode {
+      tool_name: tu.name.clone(),
+      tool_use_id: tu.id.clone(),
+      input_json: input,
+    }),
+    thinking: None,
+  }
+}
+
+/// Converts `ctxs` into a flat list of structured nodes — a `tool_result`
+/// node per prior tool call, a narrative text node for the exchange, then a
+/// `tool_use` node per new tool call the assistant made — assigning ids
+/// starting at `starting_id` and incrementing by one per node. This mirrors
+/// the request/response ordering `render_exchange_full` uses (tool results
+/// belong to the request, tool uses to the response). Validates the
+/// `tool_use`/`tool_result` id-pairing invariant before converting anything.
+pub(crate) fn build_structured_history_nodes(ctxs: &[ExchangeRenderCtx], starting_id: i64) -> Result<Vec<NodeIn>, ConversionError> {
+  validate_tool_id_pairing(ctxs)?;
+
+  let mut next_id = starting_id;
+  let mut nodes = Vec::new();
+  for ctx in ctxs {
+    for tr in &ctx.tool_results {
+      nodes.push(make_tool_result_node(next_id, tr, ctx.mode));
+      next_id += 1;
+    }
+    if let Some(text) = render_exchange_narrative(ctx) {
+      nodes.push(make_text_node(next_id, text));
+      next_id += 1;
+    }
+    for tu in &ctx.tool_uses {
+      nodes.push(make_tool_use_node(next_id, tu, ctx.mode));
+      next_id += 1;
+    }
+  }
+  Ok(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn exchange(
+    user_message: &str,
+    response_text: &str,
+    tool_results: Vec<ToolResultCtx>,
+    tool_uses: Vec<ToolUseCtx>,
+  ) -> ExchangeRenderCtx {
+    ExchangeRenderCtx {
+      user_message: user_message.to_string(),
+      tool_results,
+      thinking: String::new(),
+      response_text: response_text.to_string(),
+      tool_uses,
+      has_response: true,
+      mode: ExchangeRenderMode::Full,
+    }
+  }
+
+  fn tool_use(id: &str, name: &str) -> ToolUseCtx {
+    ToolUseCtx { name: name.to_string(), id: id.to_string(), input: r#"{"path":"a.rs"}"#.to_string() }
+  }
+
+  fn tool_result(id: &str, content: &str) -> ToolResultCtx {
+    ToolResultCtx { id: id.to_string(), content: content.to_string(), is_error: false }
+  }
+
+  #[test]
+  fn clean_conversion_produces_ordered_nodes_with_sequential_ids() {
+    let ctxs = vec![
+      exchange("read file a.rs", "", Vec::new(), vec![tool_use("t1", "read_file")]),
+      exchange("", "", vec![tool_result("t1", "CONTENTS")], Vec::new()),
+    ];
+
+    let nodes = build_structured_history_nodes(&ctxs, 10).expect("matched ids should convert cleanly");
+
+    // Exchange 0: no tool_results, a narrative node, then its tool_use.
+    // Exchange 1: just its tool_result (no narrative — nothing to say, no tool_uses).
+    assert_eq!(nodes.len(), 3);
+    assert_eq!(nodes[0].node_type, REQUEST_NODE_TEXT);
+    assert_eq!(nodes[1].node_type, RESPONSE_NODE_TOOL_USE);
+    assert_eq!(nodes[2].node_type, REQUEST_NODE_TOOL_RESULT);
+
+    let ids: Vec<i64> = nodes.iter().map(|n| n.id).collect();
+    assert_eq!(ids, vec![10, 11, 12], "ids should be sequential starting at starting_id");
+
+    let tool_use_node = nodes[1].tool_use.as_ref().expect("tool_use node should carry a ToolUseNode");
+    assert_eq!(tool_use_node.tool_use_id, "t1");
+    assert_eq!(tool_use_node.input_json, r#"{"path":"a.rs"}"#, "input_json should stay a real JSON object, not be re-embedded as text");
+
+    let tool_result_node = nodes[2].tool_result_node.as_ref().expect("tool_result node should carry a ToolResultNode");
+    assert_eq!(tool_result_node.tool_use_id, "t1");
+    assert_eq!(tool_result_node.content, "CONTENTS");
+  }
+
+  #[test]
+  fn unmatched_tool_use_from_a_non_final_exchange_is_rejected() {
+    let ctxs = vec![
+      // t1's result never arrives, and a later exchange goes on to make its
+      // own tool_use — so t1 is genuinely stale, not "still pending".
+      exchange("read file a.rs", "", Vec::new(), vec![tool_use("t1", "read_file")]),
+      exchange("read file b.rs", "", Vec::new(), vec![tool_use("t2", "read_file")]),
+    ];
+
+    let err = build_structured_history_nodes(&ctxs, 0).expect_err("unmatched tool_use id should be rejected");
+    assert_eq!(err.unmatched_tool_use_ids, vec!["t1".to_string()]);
+  }
+
+  #[test]
+  fn last_exchange_with_tool_uses_is_exempt_even_behind_a_trailing_result_only_exchange() {
+    let ctxs = vec![
+      // The real final turn: a pending tool_use with no result yet.
+      exchange("read file b.rs", "", Vec::new(), vec![tool_use("t2", "read_file")]),
+      // A synthetic trailing exchange carrying only tool_results for the
+      // current turn (as render_history_summary_node_value_with_retrieval
+      // appends for `extra_tool_results`) — it has no tool_uses of its own,
+      // so it must not steal the exemption from the exchange above.
+      exchange("", "", vec![tool_result("t0", "unrelated earlier result")], Vec::new()),
+    ];
+
+    let nodes = build_structured_history_nodes(&ctxs, 0).expect("pending tool_use on the last tool-use-bearing exchange should be exempt");
+    assert!(nodes.iter().any(|n| n.node_type == RESPONSE_NODE_TOOL_USE));
+  }
+}
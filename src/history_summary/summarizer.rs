@@ -0,0 +1,340 @@
+//! Local fallback summarization for chat histories that never received a
+//! `REQUEST_NODE_HISTORY_SUMMARY` node from upstream. Without this,
+//! `compact_chat_history` is a no-op and the full, unabridged history is
+//! passed straight through — which is exactly what overflows BYOK backends
+//! with small context windows.
+
+use serde_json::Value;
+
+use crate::protocol::{AugmentChatHistory, NodeIn, REQUEST_NODE_HISTORY_SUMMARY};
+
+use super::{
+  chat_history_item_has_summary, compact_chat_history_with_retrieval, exchange_render_ctx_from_chat_item,
+  render_exchange_abridged, HistorySummaryRenderMode, HistoryTokenBudget, RetrievalConfig,
+};
+
+/// A BYOK-backed summarization call. Kept minimal so a thin adapter around
+/// whatever completion client the caller already has is all that's needed.
+///
+/// Deliberately not `dyn`-safe: the `async fn` here relies on Rust's native
+/// async-fn-in-traits, so callers pass a concrete type rather than a trait
+/// object.
+pub trait SummarizationBackend {
+  async fn summarize(&self, prompt: &str) -> Result<String, SummarizationError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct SummarizationError(pub String);
+
+impl std::fmt::Display for SummarizationError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "summarization backend failed: {}", self.0)
+  }
+}
+
+impl std::error::Error for SummarizationError {}
+
+#[derive(Debug, Clone)]
+pub struct SummarizationConfig {
+  /// Don't bother summarizing until the history has more than this many
+  /// exchanges.
+  pub min_exchanges_before_summarize: usize,
+  /// How many of the oldest exchanges to fold into the synthesized summary.
+  /// Always leaves at least one exchange untouched.
+  pub summarize_oldest_n: usize,
+}
+
+impl Default for SummarizationConfig {
+  fn default() -> Self {
+    Self {
+      min_exchanges_before_summarize: 20,
+      summarize_oldest_n: 12,
+    }
+  }
+}
+
+const DEFAULT_MESSAGE_TEMPLATE: &str = r#"<supervisor>
+<summary>
+{summary}
+</summary>
+Beginning part has {beginning_part_dropped_num_exchanges} exchanges.
+<middle_part_abridged>
+{middle_part_abridged}
+</middle_part_abridged>
+<end_part_full>
+{end_part_full}
+</end_part_full>
+</supervisor>"#;
+
+/// Renders `oldest` abridged (thinking dropped, tool payloads truncated to a
+/// head+tail window) rather than at full fidelity — this prompt is itself
+/// sent to the configured BYOK backend, so an untruncated tool-heavy session
+/// could blow its context window before any summary comes back.
+fn build_summarization_prompt(oldest: &[AugmentChatHistory]) -> String {
+  let rendered = oldest
+    .iter()
+    .map(exchange_render_ctx_from_chat_item)
+    .map(|ctx| render_exchange_abridged(&ctx))
+    .collect::<Vec<_>>()
+    .join("\n");
+
+  format!(
+    "Summarize the following conversation history concisely, preserving any \
+     decisions, open tasks, and file paths mentioned. Respond with prose \
+     only, no preamble.\n\n{rendered}"
+  )
+}
+
+fn build_abridged_middle_text(oldest: &[AugmentChatHistory]) -> String {
+  oldest
+    .iter()
+    .map(exchange_render_ctx_from_chat_item)
+    .map(|ctx| render_exchange_abridged(&ctx))
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+fn inject_synthesized_summary_node(chat_history: &mut [AugmentChatHistory], host_index: usize, summary_value: Value) {
+  let Some(host) = chat_history.get_mut(host_index) else {
+    return;
+  };
+  host.request_nodes.insert(
+    0,
+    NodeIn {
+      id: 0,
+      node_type: REQUEST_NODE_HISTORY_SUMMARY,
+      content: String::new(),
+      text_node: None,
+      tool_result_node: None,
+      image_node: None,
+      image_id_node: None,
+      ide_state_node: None,
+      edit_events_node: None,
+      checkpoint_ref_node: None,
+      change_personality_node: None,
+      file_node: None,
+      file_id_node: None,
+      history_summary_node: Some(summary_value),
+      tool_use: None,
+      thinking: None,
+    },
+  );
+}
+
+/// When `chat_history` has no history-summary node and has grown past
+/// `config.min_exchanges_before_summarize`, calls `backend` to summarize
+/// the oldest `config.summarize_oldest_n` exchanges and synthesizes a
+/// `HistorySummaryNode` so `compact_chat_history`'s existing rendering path
+/// can take over from here on. Fails open: if the backend call errors or
+/// returns nothing usable, `chat_history` is left untouched.
+pub async fn maybe_summarize_history<B: SummarizationBackend>(
+  chat_history: &mut Vec<AugmentChatHistory>,
+  backend: &B,
+  config: &SummarizationConfig,
+) {
+  maybe_summarize_history_with_budget(chat_history, backend, config, None).await
+}
+
+/// Same as `maybe_summarize_history`, but threads a `HistoryTokenBudget`
+/// through to the `compact_chat_history` pass that follows synthesis, so
+/// the just-synthesized summary is itself rendered under the same
+/// token-budget protection as a backend-provided one.
+pub async fn maybe_summarize_history_with_budget<B: SummarizationBackend>(
+  chat_history: &mut Vec<AugmentChatHistory>,
+  backend: &B,
+  config: &SummarizationConfig,
+  budget: Option<&HistoryTokenBudget>,
+) {
+  maybe_summarize_history_with_mode(chat_history, backend, config, budget, HistorySummaryRenderMode::XmlEmbedded).await
+}
+
+/// Same as `maybe_summarize_history_with_budget`, but `mode` selects how the
+/// tool-call portion of the rendered history is emitted — see
+/// `compact_chat_history_with_mode`.
+pub async fn maybe_summarize_history_with_mode<B: SummarizationBackend>(
+  chat_history: &mut Vec<AugmentChatHistory>,
+  backend: &B,
+  config: &SummarizationConfig,
+  budget: Option<&HistoryTokenBudget>,
+  mode: HistorySummaryRenderMode,
+) {
+  maybe_summarize_history_with_retrieval(chat_history, backend, config, budget, mode, &RetrievalConfig::default()).await
+}
+
+/// Same as `maybe_summarize_history_with_mode`, but `retrieval` is forwarded
+/// to `compact_chat_history_with_retrieval` so exchanges this call drops are
+/// still eligible for re-injection — see `compact_chat_history_with_retrieval`.
+pub async fn maybe_summarize_history_with_retrieval<B: SummarizationBackend>(
+  chat_history: &mut Vec<AugmentChatHistory>,
+  backend: &B,
+  config: &SummarizationConfig,
+  budget: Option<&HistoryTokenBudget>,
+  mode: HistorySummaryRenderMode,
+  retrieval: &RetrievalConfig,
+) {
+  if chat_history.iter().any(chat_history_item_has_summary) {
+    return;
+  }
+  if chat_history.len() <= config.min_exchanges_before_summarize {
+    return;
+  }
+
+  let n = config.summarize_oldest_n.min(chat_history.len().saturating_sub(1));
+  if n == 0 {
+    return;
+  }
+
+  let oldest = &chat_history[..n];
+  let prompt = build_summarization_prompt(oldest);
+
+  let summary_text = match backend.summarize(&prompt).await {
+    Ok(s) if !s.trim().is_empty() => s,
+    _ => return,
+  };
+  let middle_abridged = build_abridged_middle_text(oldest);
+
+  let summary_value = serde_json::json!({
+    "summary_text": summary_text,
+    "summarization_request_id": "",
+    "history_beginning_dropped_num_exchanges": n as i64,
+    "history_middle_abridged_text": middle_abridged,
+    "history_end": [],
+    "message_template": DEFAULT_MESSAGE_TEMPLATE,
+  });
+
+  inject_synthesized_summary_node(chat_history, n, summary_value);
+  compact_chat_history_with_retrieval(chat_history, budget, mode, retrieval);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::protocol::REQUEST_NODE_TEXT;
+
+  struct EchoBackend;
+
+  impl SummarizationBackend for EchoBackend {
+    async fn summarize(&self, _prompt: &str) -> Result<String, SummarizationError> {
+      Ok("SYNTHESIZED SUMMARY".to_string())
+    }
+  }
+
+  struct FailingBackend;
+
+  impl SummarizationBackend for FailingBackend {
+    async fn summarize(&self, _prompt: &str) -> Result<String, SummarizationError> {
+      Err(SummarizationError("backend unavailable".to_string()))
+    }
+  }
+
+  fn make_exchange(n: usize) -> AugmentChatHistory {
+    AugmentChatHistory {
+      response_text: format!("response {n}"),
+      request_message: format!("message {n}"),
+      request_id: format!("r{n}"),
+      request_nodes: Vec::new(),
+      structured_request_nodes: Vec::new(),
+      nodes: Vec::new(),
+      response_nodes: Vec::new(),
+      structured_output_nodes: Vec::new(),
+    }
+  }
+
+  struct RecordingBackend {
+    last_prompt: std::cell::RefCell<String>,
+  }
+
+  impl SummarizationBackend for RecordingBackend {
+    async fn summarize(&self, prompt: &str) -> Result<String, SummarizationError> {
+      *self.last_prompt.borrow_mut() = prompt.to_string();
+      Ok("SYNTHESIZED SUMMARY".to_string())
+    }
+  }
+
+  #[tokio::test]
+  async fn summarization_prompt_is_abridged_not_full_fidelity() {
+    let mut chat_history: Vec<AugmentChatHistory> = (0..25).map(make_exchange).collect();
+    // One huge tool-heavy exchange among the oldest ones being summarized.
+    chat_history[0].request_nodes.push(NodeIn {
+      id: 1,
+      node_type: crate::protocol::REQUEST_NODE_TOOL_RESULT,
+      content: String::new(),
+      text_node: None,
+      tool_result_node: Some(crate::protocol::ToolResultNode {
+        tool_use_id: "t1".to_string(),
+        content: "x".repeat(5000),
+        content_nodes: Vec::new(),
+        is_error: false,
+      }),
+      image_node: None,
+      image_id_node: None,
+      ide_state_node: None,
+      edit_events_node: None,
+      checkpoint_ref_node: None,
+      change_personality_node: None,
+      file_node: None,
+      file_id_node: None,
+      history_summary_node: None,
+      tool_use: None,
+      thinking: None,
+    });
+    let config = SummarizationConfig {
+      min_exchanges_before_summarize: 20,
+      summarize_oldest_n: 12,
+    };
+    let backend = RecordingBackend { last_prompt: std::cell::RefCell::new(String::new()) };
+
+    maybe_summarize_history(&mut chat_history, &backend, &config).await;
+
+    let prompt = backend.last_prompt.borrow().clone();
+    assert!(
+      prompt.len() < 5000,
+      "summarization prompt should be abridged, not carry the full 5000-char payload"
+    );
+    assert!(prompt.contains("[elided for history compaction]"));
+  }
+
+  #[tokio::test]
+  async fn synthesizes_summary_when_history_grows_large() {
+    let mut chat_history: Vec<AugmentChatHistory> = (0..25).map(make_exchange).collect();
+    let config = SummarizationConfig {
+      min_exchanges_before_summarize: 20,
+      summarize_oldest_n: 12,
+    };
+
+    maybe_summarize_history(&mut chat_history, &EchoBackend, &config).await;
+
+    assert_eq!(chat_history.len(), 25 - 12);
+    let text = chat_history[0]
+      .request_nodes
+      .iter()
+      .find(|n| n.node_type == REQUEST_NODE_TEXT)
+      .and_then(|n| n.text_node.as_ref())
+      .map(|t| t.content.clone())
+      .unwrap_or_default();
+    assert!(text.contains("SYNTHESIZED SUMMARY"));
+  }
+
+  #[tokio::test]
+  async fn leaves_history_untouched_below_threshold() {
+    let mut chat_history: Vec<AugmentChatHistory> = (0..5).map(make_exchange).collect();
+    let config = SummarizationConfig::default();
+
+    maybe_summarize_history(&mut chat_history, &EchoBackend, &config).await;
+
+    assert_eq!(chat_history.len(), 5);
+  }
+
+  #[tokio::test]
+  async fn fails_open_when_backend_errors() {
+    let mut chat_history: Vec<AugmentChatHistory> = (0..25).map(make_exchange).collect();
+    let config = SummarizationConfig {
+      min_exchanges_before_summarize: 20,
+      summarize_oldest_n: 12,
+    };
+
+    maybe_summarize_history(&mut chat_history, &FailingBackend, &config).await;
+
+    assert_eq!(chat_history.len(), 25);
+  }
+}
@@ -0,0 +1,61 @@
+//! Pluggable token counting for the history-compaction token budget.
+
+/// Counts how many "tokens" a string would consume against a model's context
+/// window. Implementations don't need to be exact — they just need to be
+/// consistent enough to drive the progressive-abridging loop in
+/// `compact_chat_history`.
+pub trait TokenCounter: Send + Sync {
+  fn count(&self, s: &str) -> usize;
+}
+
+/// Default counter used when no BPE tokenizer is wired up. Approximates the
+/// common "~4 chars per token" rule of thumb, floored by the
+/// whitespace-separated word count so text made of many very short words
+/// isn't under-counted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicTokenCounter;
+
+impl TokenCounter for HeuristicTokenCounter {
+  fn count(&self, s: &str) -> usize {
+    let chars = s.chars().count();
+    let by_chars = chars.div_ceil(4);
+    let words = s.split_whitespace().count();
+    by_chars.max(words)
+  }
+}
+
+/// Exact BPE-backed counter for callers that have a tiktoken-compatible
+/// tokenizer available. Gated behind the `tiktoken` feature so the default
+/// build doesn't pull in the extra dependency.
+#[cfg(feature = "tiktoken")]
+pub struct BpeTokenCounter {
+  bpe: tiktoken_rs::CoreBPE,
+}
+
+#[cfg(feature = "tiktoken")]
+impl BpeTokenCounter {
+  pub fn cl100k() -> anyhow::Result<Self> {
+    Ok(Self {
+      bpe: tiktoken_rs::cl100k_base()?,
+    })
+  }
+}
+
+#[cfg(feature = "tiktoken")]
+impl TokenCounter for BpeTokenCounter {
+  fn count(&self, s: &str) -> usize {
+    self.bpe.encode_ordinary(s).len()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn heuristic_counts_scale_with_length() {
+    let counter = HeuristicTokenCounter;
+    assert!(counter.count("hello world") > 0);
+    assert!(counter.count("a much longer sentence with many more words in it") > counter.count("short"));
+  }
+}